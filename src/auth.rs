@@ -0,0 +1,162 @@
+//! Interactive OAuth login for the Spotify Web API, with per-user token
+//! caching so more than one account can be used from this machine.
+//!
+//! Unlike `rspotify::spotify::util::get_token`, which assumes a single
+//! cached token file, tokens here are stored in a sled tree keyed by
+//! Spotify user ID, and the client config is discovered under
+//! `$XDG_CONFIG_HOME` instead of a hardcoded path.
+
+use crate::Error;
+use log::{info, warn};
+use rspotify::spotify::client::Spotify;
+use rspotify::spotify::oauth2::{SpotifyClientCredentials, SpotifyOAuth, TokenInfo};
+use rspotify::spotify::util::process_token;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tiny_http::{Response, Server};
+
+const SCOPES: [&str; 4] = [
+    "playlist-read-collaborative",
+    "playlist-read-private",
+    "user-library-read",
+    "user-read-private",
+];
+
+const REDIRECT_URI: &str = "http://localhost:8888/callback";
+const CALLBACK_ADDR: &str = "127.0.0.1:8888";
+
+#[derive(Deserialize)]
+struct ClientConfig {
+    client_id: String,
+    client_secret: String,
+}
+
+fn config_dir() -> PathBuf {
+    if let Some(xdg_config_home) = std::env::var_os("XDG_CONFIG_HOME") {
+        return PathBuf::from(xdg_config_home).join("spotify-api-playground");
+    }
+    let home = std::env::var_os("HOME").unwrap_or_default();
+    PathBuf::from(home)
+        .join(".config")
+        .join("spotify-api-playground")
+}
+
+fn load_client_config() -> Result<ClientConfig, Error> {
+    let path = config_dir().join("client.yml");
+    let data = std::fs::read_to_string(&path)?;
+    Ok(serde_yaml::from_str(&data)?)
+}
+
+/// Authenticates as `user_id` if a token for that account is already
+/// cached in `db`, or runs the interactive login flow otherwise. When
+/// `user_id` is `None` and exactly one account's token is cached, that
+/// account is used instead of starting the interactive flow, so a repeat
+/// run with a single known account doesn't need to specify it explicitly.
+/// Newly authenticated tokens are cached under the ID of the account that
+/// completed the flow, regardless of `user_id`, since that's the only
+/// point at which the account is actually known. Returns the client
+/// alongside the Spotify user ID it authenticated as, so callers can scope
+/// per-user data (e.g. cached library contents) by that ID.
+pub fn authenticate(db: &sled::Db, user_id: Option<&str>) -> Result<(Spotify, String), Error> {
+    let tokens = db.open_tree("oauth_tokens")?;
+
+    if let Some(user_id) = user_id {
+        if let Some(ivec) = tokens.get(user_id)? {
+            let token_info: TokenInfo = serde_cbor::from_reader(ivec.as_ref())?;
+            return Ok((build_client(token_info), user_id.to_string()));
+        }
+    } else if let Some((user_id, token_info)) = sole_cached_user(&tokens)? {
+        return Ok((build_client(token_info), user_id));
+    }
+
+    let client_config = load_client_config()?;
+    let mut oauth = SpotifyOAuth::default()
+        .client_id(&client_config.client_id)
+        .client_secret(&client_config.client_secret)
+        .redirect_uri(REDIRECT_URI)
+        .scope(&SCOPES.join(" "))
+        .build();
+
+    let code = await_authorization_code(&oauth)?;
+    let token_info = process_token(&mut oauth, &code).ok_or_else(|| {
+        Error::Auth("Spotify did not exchange the authorization code for a token".to_string())
+    })?;
+
+    let spotify = build_client(token_info.clone());
+    let me = spotify.me()?;
+    let mut serialized = Vec::new();
+    serde_cbor::to_writer(&mut serialized, &token_info)?;
+    tokens.insert(&me.id, serialized)?;
+
+    Ok((spotify, me.id))
+}
+
+/// Returns the cached user ID and token from `tokens` if it holds exactly
+/// one entry, or `None` if it's empty or ambiguous (more than one account
+/// cached, requiring the caller to specify which one to use).
+fn sole_cached_user(tokens: &sled::Tree) -> Result<Option<(String, TokenInfo)>, Error> {
+    let mut entries = tokens.iter();
+    let first = match entries.next() {
+        Some(entry) => entry?,
+        None => return Ok(None),
+    };
+    if entries.next().is_some() {
+        return Ok(None);
+    }
+    let (user_id, ivec) = first;
+    let user_id = String::from_utf8_lossy(&user_id).into_owned();
+    let token_info: TokenInfo = serde_cbor::from_reader(ivec.as_ref())?;
+    Ok(Some((user_id, token_info)))
+}
+
+fn build_client(token_info: TokenInfo) -> Spotify {
+    let client_creds = SpotifyClientCredentials::default()
+        .token_info(token_info)
+        .build();
+    Spotify::default()
+        .client_credentials_manager(client_creds)
+        .build()
+}
+
+/// Opens the Spotify authorize URL in a browser, then listens on
+/// `REDIRECT_URI`'s host and port for the resulting callback long enough
+/// to pull the `code` query parameter out of it.
+fn await_authorization_code(oauth: &SpotifyOAuth) -> Result<String, Error> {
+    let server = Server::http(CALLBACK_ADDR).map_err(|e| {
+        Error::Auth(format!(
+            "couldn't start local OAuth callback server on {}: {}",
+            CALLBACK_ADDR, e
+        ))
+    })?;
+
+    let authorize_url = oauth.get_authorize_url(None, None);
+    info!("Opening browser to authorize Spotify access: {}", authorize_url);
+    if webbrowser::open(&authorize_url).is_err() {
+        warn!(
+            "Couldn't open a browser automatically; visit this URL to authorize access: {}",
+            authorize_url
+        );
+    }
+
+    let request = server
+        .recv()
+        .map_err(|e| Error::Auth(format!("error receiving OAuth callback: {}", e)))?;
+    let code = extract_code(request.url()).ok_or_else(|| {
+        Error::Auth("OAuth callback request didn't include an authorization code".to_string())
+    })?;
+    let response = Response::from_string(
+        "Authentication complete, you can close this tab and return to the terminal.",
+    );
+    request
+        .respond(response)
+        .map_err(|e| Error::Auth(format!("error responding to OAuth callback: {}", e)))?;
+    Ok(code)
+}
+
+fn extract_code(url: &str) -> Option<String> {
+    let query = url.splitn(2, '?').nth(1)?;
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("code="))
+        .map(str::to_string)
+}
@@ -1,8 +1,9 @@
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use rspotify::spotify::model::{album::SimplifiedAlbum, playlist::PlaylistTrack};
-use spotify_api_playground::{CachingSpotify, Error, PlaylistTracks};
-use std::cmp::Ordering;
+use spotify_api_playground::{
+    playlist_track_sort_cmp, CachingSpotify, Error, PlaylistTracks, SetOp,
+};
 
 fn parse_playlist_link(url: &str) -> Option<String> {
     static REGEX: OnceCell<Regex> = OnceCell::new();
@@ -31,35 +32,7 @@ fn year(album: &SimplifiedAlbum) -> Option<u16> {
     }
 }
 
-fn playlist_track_sort_cmp(a: &PlaylistTrack, b: &PlaylistTrack) -> Ordering {
-    match a.track.album.release_date.cmp(&b.track.album.release_date) {
-        Ordering::Equal => {}
-        other => return other,
-    }
-    for (a_artist, b_artist) in a.track.artists.iter().zip(b.track.artists.iter()) {
-        match a_artist.name.cmp(&b_artist.name) {
-            Ordering::Equal => continue,
-            other => return other,
-        }
-    }
-    match a.track.artists.len().cmp(&b.track.artists.len()) {
-        Ordering::Equal => {}
-        other => return other,
-    }
-    match a.track.album.name.cmp(&b.track.album.name) {
-        Ordering::Equal => {}
-        other => return other,
-    }
-    match a.track.track_number.cmp(&b.track.track_number) {
-        Ordering::Equal => {}
-        other => return other,
-    }
-    a.track.name.cmp(&b.track.name)
-}
-
-fn print_playlist(iter: PlaylistTracks) -> Result<(), Error> {
-    let tracks: Result<Vec<PlaylistTrack>, Error> = iter.collect();
-    let mut tracks = tracks?;
+fn print_tracks(mut tracks: Vec<PlaylistTrack>) {
     println!("{} tracks", tracks.len());
     tracks.sort_unstable_by(playlist_track_sort_cmp);
     let no_url_string = "(no URL)".to_string();
@@ -82,28 +55,75 @@ fn print_playlist(iter: PlaylistTracks) -> Result<(), Error> {
             },
         );
     }
+}
+
+fn print_playlist(iter: PlaylistTracks) -> Result<(), Error> {
+    let tracks: Result<Vec<PlaylistTrack>, Error> = iter.collect();
+    print_tracks(tracks?);
     Ok(())
 }
 
+fn parse_set_op(arg: &str) -> Option<SetOp> {
+    match arg {
+        "--intersection" => Some(SetOp::Intersection),
+        "--difference" => Some(SetOp::Difference),
+        "--union" => Some(SetOp::Union),
+        _ => None,
+    }
+}
+
+/// Pulls the first `--user <id>` pair out of `args`, wherever it appears,
+/// selecting which
+/// cached account to authenticate as when more than one is cached.
+fn parse_user_flag(args: &mut Vec<String>) -> Option<String> {
+    let flag_index = args.iter().position(|arg| arg == "--user")?;
+    if flag_index + 1 >= args.len() {
+        return None;
+    }
+    args.remove(flag_index);
+    Some(args.remove(flag_index))
+}
+
 fn main() -> Result<(), Error> {
     simple_logger::init_with_level(log::Level::Warn).unwrap();
-    let arg = match std::env::args().skip(1).next() {
-        Some(arg) => arg,
-        None => {
-            println!(
-                "This command expects a Spotify playlist link or ID as a command line argument"
-            );
-            return Ok(());
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let user_id = parse_user_flag(&mut args);
+    if args.is_empty() {
+        println!(
+            "This command expects a Spotify playlist link or ID as a command line argument, \
+             or a set operation flag (--intersection, --difference, --union) followed by two \
+             or more playlist links, optionally preceded by --user <id> to select a cached \
+             account"
+        );
+        return Ok(());
+    }
+    let spotify = CachingSpotify::with_user(user_id.as_deref())?;
+    match parse_set_op(&args[0]) {
+        Some(op) => {
+            let playlist_ids: Option<Vec<String>> = args[1..]
+                .iter()
+                .map(|arg| parse_playlist_link(arg))
+                .collect();
+            let playlist_ids = match playlist_ids {
+                Some(ids) if ids.len() >= 2 => ids,
+                _ => {
+                    println!("Expected two or more playlist links after the set operation flag");
+                    return Ok(());
+                }
+            };
+            let playlist_ids: Vec<&str> = playlist_ids.iter().map(String::as_str).collect();
+            print_tracks(spotify.compare_playlists(&playlist_ids, op)?);
         }
-    };
-    let playlist_id = match parse_playlist_link(arg.as_str()) {
-        Some(playlist_id) => playlist_id,
         None => {
-            println!("Couldn't parse playlist ID from argument");
-            return Ok(());
+            let playlist_id = match parse_playlist_link(&args[0]) {
+                Some(playlist_id) => playlist_id,
+                None => {
+                    println!("Couldn't parse playlist ID from argument");
+                    return Ok(());
+                }
+            };
+            print_playlist(spotify.playlist_tracks(&playlist_id, false)?)?;
         }
-    };
-    let spotify = CachingSpotify::new()?;
-    print_playlist(spotify.playlist_tracks(&playlist_id, false)?)?;
+    }
     Ok(())
 }
@@ -1,13 +1,22 @@
-use log::error;
+mod auth;
+
+use log::{error, warn};
 use rspotify::spotify::{
-    client::Spotify,
-    model::playlist::PlaylistTrack,
-    oauth2::{SpotifyClientCredentials, SpotifyOAuth},
+    client::{ApiError, Spotify},
+    model::{
+        album::SavedAlbum,
+        page::Page,
+        playlist::{PlaylistTrack, SimplifiedPlaylist},
+        track::SavedTrack,
+    },
 };
-use serde::Deserialize;
-use std::collections::VecDeque;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Error {
@@ -16,6 +25,10 @@ pub enum Error {
     Sled(sled::Error),
     Failure(failure::Error),
     Cbor(serde_cbor::Error),
+    /// Authentication failed, e.g. the local OAuth callback server
+    /// couldn't bind, the callback didn't carry an authorization code, or
+    /// Spotify didn't exchange it for a token.
+    Auth(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -48,143 +61,455 @@ impl From<serde_cbor::Error> for Error {
     }
 }
 
-const SCOPES: [&str; 4] = [
-    "playlist-read-collaborative",
-    "playlist-read-private",
-    "user-library-read",
-    "user-read-private",
-];
+/// Orders playlist tracks by release date, then artist(s), album, track
+/// number, and finally title, for stable and readable output.
+pub fn playlist_track_sort_cmp(a: &PlaylistTrack, b: &PlaylistTrack) -> Ordering {
+    match a.track.album.release_date.cmp(&b.track.album.release_date) {
+        Ordering::Equal => {}
+        other => return other,
+    }
+    for (a_artist, b_artist) in a.track.artists.iter().zip(b.track.artists.iter()) {
+        match a_artist.name.cmp(&b_artist.name) {
+            Ordering::Equal => continue,
+            other => return other,
+        }
+    }
+    match a.track.artists.len().cmp(&b.track.artists.len()) {
+        Ordering::Equal => {}
+        other => return other,
+    }
+    match a.track.album.name.cmp(&b.track.album.name) {
+        Ordering::Equal => {}
+        other => return other,
+    }
+    match a.track.track_number.cmp(&b.track.track_number) {
+        Ordering::Equal => {}
+        other => return other,
+    }
+    a.track.name.cmp(&b.track.name)
+}
 
-#[derive(Deserialize)]
-struct ClientConfig {
-    pub client_id: String,
-    pub client_secret: String,
-    pub device_id: Option<String>,
+/// Builds the fallback key used by [`track_key`] for local or otherwise
+/// ID-less tracks, from fields that distinguish one track from another
+/// rather than a shared empty string.
+fn local_track_key(name: &str, artists: &[&str], album: &str, duration_ms: u32) -> String {
+    format!("{}|{}|{}|{}", name, artists.join(","), album, duration_ms)
 }
 
-impl ClientConfig {
-    fn new() -> Self {
-        Self {
-            client_id: "".to_string(),
-            client_secret: "".to_string(),
-            device_id: None,
-        }
+/// Identifies a playlist track for set operations, preferring its Spotify
+/// track ID, then its web player URL, and finally [`local_track_key`] for
+/// local or otherwise ID-less tracks.
+fn track_key(track: &PlaylistTrack) -> String {
+    track
+        .track
+        .id
+        .clone()
+        .or_else(|| track.track.external_urls.get("spotify").cloned())
+        .unwrap_or_else(|| {
+            let artists: Vec<&str> = track
+                .track
+                .artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect();
+            local_track_key(
+                &track.track.name,
+                &artists,
+                &track.track.album.name,
+                track.track.duration_ms,
+            )
+        })
+}
+
+/// The set operation to apply when comparing multiple playlists in
+/// [`CachingSpotify::compare_playlists`].
+#[derive(Debug, Clone, Copy)]
+pub enum SetOp {
+    /// Tracks present in every playlist.
+    Intersection,
+    /// Tracks present in the first playlist but none of the others.
+    Difference,
+    /// Tracks present in any playlist.
+    Union,
+}
+
+/// Folds `op` across a sequence of key sets, one per playlist, the way
+/// [`CachingSpotify::compare_playlists`] does to decide which track keys
+/// survive into the result. An empty sequence yields an empty set.
+fn apply_set_op(op: SetOp, mut sets: impl Iterator<Item = HashSet<String>>) -> HashSet<String> {
+    let mut result = match sets.next() {
+        Some(first) => first,
+        None => return HashSet::new(),
+    };
+    for keys in sets {
+        result = match op {
+            SetOp::Intersection => &result & &keys,
+            SetOp::Difference => &result - &keys,
+            SetOp::Union => &result | &keys,
+        };
     }
+    result
+}
 
-    fn load_config(&mut self) -> Result<(), Error> {
-        let path = PathBuf::from("/home/david/.config/spotify-tui/client.yml");
-        let data = std::fs::read_to_string(&path)?;
-        let config_yml: ClientConfig = serde_yaml::from_str(&data)?;
+const SEARCH_LIMIT: u32 = 20;
+const MAX_RETRIES: u32 = 5;
+const DEFAULT_RATE_LIMIT_RETRY_SECS: u64 = 5;
 
-        self.client_id = config_yml.client_id;
-        self.client_secret = config_yml.client_secret;
-        self.device_id = config_yml.device_id;
+/// Builds a cache key prefix for `user_id` that can't alias another user's,
+/// by leading with the ID's length as a 4-byte big-endian tag before the ID
+/// bytes themselves. Without the length tag, a `scan_prefix` keyed on a raw
+/// user ID would also match any other cached user ID it happens to be a
+/// byte-prefix of (e.g. `"dave"` matching `"davecompany"`).
+fn user_key_prefix(user_id: &str) -> Vec<u8> {
+    let mut key = (user_id.len() as u32).to_be_bytes().to_vec();
+    key.extend_from_slice(user_id.as_bytes());
+    key
+}
 
-        Ok(())
+/// Drops every cached page entry and the cached length under `length_key`,
+/// used when the underlying collection is known to have changed (e.g. a
+/// playlist's `snapshot_id` no longer matches). Cached pages are keyed by
+/// `item_key_prefix` followed by a 4-byte big-endian offset, so a prefix
+/// range scan finds all of them.
+fn clear_cached_pages(
+    tracks_tree: &sled::Tree,
+    length_tree: &sled::Tree,
+    item_key_prefix: &[u8],
+    length_key: &[u8],
+) -> Result<(), Error> {
+    let keys: Vec<sled::IVec> = tracks_tree
+        .scan_prefix(item_key_prefix)
+        .keys()
+        .collect::<Result<_, _>>()?;
+    for key in keys {
+        tracks_tree.remove(key)?;
     }
+    length_tree.remove(length_key)?;
+    Ok(())
 }
 
-fn auth() -> Result<Spotify, Error> {
-    let mut client_config = ClientConfig::new();
-    client_config.load_config()?;
+/// Computes how long to wait before the `attempt_count`-th retry of a
+/// request that failed with `error`. Rate limit responses
+/// (`ApiError::RateLimited`) wait for the `Retry-After` duration Spotify
+/// reports (or `DEFAULT_RATE_LIMIT_RETRY_SECS` if none is given); other
+/// transient errors back off exponentially from that same base.
+fn retry_delay_secs(attempt_count: u32, error: &failure::Error) -> u64 {
+    match error.downcast_ref::<ApiError>() {
+        Some(ApiError::RateLimited(retry_after)) => retry_after
+            .map(u64::from)
+            .unwrap_or(DEFAULT_RATE_LIMIT_RETRY_SECS),
+        _ => DEFAULT_RATE_LIMIT_RETRY_SECS * 2u64.pow(attempt_count - 1),
+    }
+}
 
-    let mut oauth = SpotifyOAuth::default()
-        .client_id(&client_config.client_id)
-        .client_secret(&client_config.client_secret)
-        .redirect_uri("http://localhost:8888/callback")
-        .cache_path(PathBuf::from(
-            "/home/david/.config/spotify-tui/.spotify_token_cache.json",
-        ))
-        .scope(&SCOPES.join(" "))
-        .build();
-    let token = oauth
-        .get_cached_token()
-        .expect("Spotify authentication token not present");
-    let client_creds = SpotifyClientCredentials::default()
-        .token_info(token)
-        .build();
-    let spotify = Spotify::default()
-        .client_credentials_manager(client_creds)
-        .build();
-    Ok(spotify)
+/// Calls `attempt`, transparently retrying on errors rather than letting
+/// them abort whatever larger operation it's part of. See
+/// [`retry_delay_secs`] for the retry delay. Either way, retries are capped
+/// at `MAX_RETRIES` so a persistent failure still surfaces as
+/// `Error::Failure`.
+fn retry_with_backoff<T>(
+    mut attempt: impl FnMut() -> Result<T, failure::Error>,
+) -> Result<T, Error> {
+    let mut attempt_count = 0;
+    loop {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                attempt_count += 1;
+                if attempt_count > MAX_RETRIES {
+                    return Err(Error::Failure(e));
+                }
+                let retry_secs = retry_delay_secs(attempt_count, &e);
+                warn!(
+                    "Error on attempt {}/{}, retrying in {}s: {:?}",
+                    attempt_count, MAX_RETRIES, retry_secs, e
+                );
+                thread::sleep(Duration::from_secs(retry_secs));
+            }
+        }
+    }
 }
 
-const SEARCH_LIMIT: u32 = 20;
+/// Fetches one page through `fetch_page`, transparently retrying on errors
+/// rather than letting them abort the whole paginated fetch. See
+/// [`retry_with_backoff`] for the retry/backoff behavior.
+fn fetch_page_with_retry<T>(
+    fetch_page: &mut impl FnMut(u32, u32) -> Result<Page<T>, failure::Error>,
+    offset: u32,
+    limit: u32,
+) -> Result<Page<T>, Error> {
+    retry_with_backoff(|| fetch_page(offset, limit))
+}
+
+/// Builds a [`CachedPaginated`] iterator, either resuming from a cached
+/// total (picking up where a previous run left off) or fetching and
+/// caching the first page when `force` is set or nothing is cached yet.
+fn open_cached_paginated<T, F>(
+    length_tree: sled::Tree,
+    tracks_tree: sled::Tree,
+    length_key: Vec<u8>,
+    item_key_prefix: Vec<u8>,
+    force: bool,
+    mut fetch_page: F,
+) -> Result<CachedPaginated<T, F>, Error>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(u32, u32) -> Result<Page<T>, failure::Error>,
+{
+    let total = if force {
+        None
+    } else {
+        match length_tree.get(&length_key)? {
+            Some(ivec) => ivec.as_ref().try_into().ok().map(u32::from_be_bytes),
+            None => None,
+        }
+    };
+    let mut key = item_key_prefix;
+    key.extend(&[0, 0, 0, 0]);
+    match total {
+        Some(total) => Ok(CachedPaginated {
+            fetch_page,
+            limit: SEARCH_LIMIT,
+            total,
+            offset: 0,
+            key,
+            buffer: VecDeque::new(),
+            tree: tracks_tree,
+        }),
+        None => {
+            let first_page = fetch_page_with_retry(&mut fetch_page, 0, SEARCH_LIMIT)?;
+            for (i, item) in first_page.items.iter().enumerate() {
+                let mut item_key = key.clone();
+                let offset_position = item_key.len() - 4;
+                item_key[offset_position..].copy_from_slice(&(i as u32).to_be_bytes());
+                let mut serialized = Vec::new();
+                serde_cbor::to_writer(&mut serialized, item)?;
+                tracks_tree.insert(item_key, serialized)?;
+            }
+            length_tree.insert(length_key, &first_page.total.to_be_bytes())?;
+            Ok(CachedPaginated {
+                fetch_page,
+                limit: SEARCH_LIMIT,
+                total: first_page.total,
+                offset: 0,
+                key,
+                buffer: first_page.items.into(),
+                tree: tracks_tree,
+            })
+        }
+    }
+}
 
 pub struct CachingSpotify {
     spotify: Spotify,
     db: sled::Db,
+    /// The Spotify user ID authenticated as, used to scope per-user cached
+    /// data (saved tracks/albums, playlists) so that switching accounts
+    /// doesn't serve one user's cached library to another.
+    user_id: String,
 }
 
 impl CachingSpotify {
     pub fn new() -> Result<CachingSpotify, Error> {
+        Self::with_user(None)
+    }
+
+    /// Authenticates as `user_id` if a token for that account is already
+    /// cached, as the only cached account if `user_id` is `None` and exactly
+    /// one is cached, or as whichever account completes the interactive
+    /// login flow otherwise (see [`auth::authenticate`]). Tokens for multiple
+    /// accounts can coexist in the cache, so switching accounts only
+    /// requires passing a different `user_id`.
+    pub fn with_user(user_id: Option<&str>) -> Result<CachingSpotify, Error> {
+        let db = sled::open("cache")?;
+        let (spotify, user_id) = auth::authenticate(&db, user_id)?;
         Ok(CachingSpotify {
-            spotify: auth()?,
-            db: sled::open("cache")?,
+            spotify,
+            db,
+            user_id,
         })
     }
 
     pub fn playlist_tracks(&self, playlist_id: &str, force: bool) -> Result<PlaylistTracks, Error> {
         let length_tree = self.db.open_tree("playlist_length")?;
         let tracks_tree = self.db.open_tree("playlist_tracks")?;
-        let total = if force {
-            None
-        } else {
-            match length_tree.get(playlist_id)? {
-                Some(ivec) => match ivec.as_ref().try_into() {
-                    Ok(array) => Some(u32::from_be_bytes(array)),
-                    Err(_) => None,
-                },
-                None => None,
-            }
-        };
-        let mut key = playlist_id.to_string().into_bytes();
-        key.extend(&[0, 0, 0, 0]);
-        match total {
-            Some(total) => Ok(PlaylistTracks {
-                spotify: &self.spotify,
-                playlist_id: playlist_id.to_string(),
-                total,
-                offset: 0,
-                key,
-                buffer: VecDeque::new(),
-                tree: tracks_tree,
-            }),
-            None => {
-                let first_page = self.spotify.user_playlist_tracks(
+        let snapshot_tree = self.db.open_tree("playlist_snapshot")?;
+
+        let snapshot_id =
+            retry_with_backoff(|| self.spotify.playlist(playlist_id, None, None))?.snapshot_id;
+        let cached_snapshot_id = snapshot_tree.get(playlist_id)?;
+        let force = force || cached_snapshot_id.as_deref() != Some(snapshot_id.as_bytes());
+        let key_prefix = playlist_id.as_bytes().to_vec();
+        if force {
+            clear_cached_pages(&tracks_tree, &length_tree, &key_prefix, &key_prefix)?;
+            snapshot_tree.insert(playlist_id, snapshot_id.as_bytes())?;
+        }
+
+        let owned_playlist_id = playlist_id.to_string();
+        let fetch_page: Box<dyn FnMut(u32, u32) -> Result<Page<PlaylistTrack>, failure::Error>> =
+            Box::new(move |offset, limit| {
+                self.spotify.user_playlist_tracks(
                     "", // user id, no longer required
-                    playlist_id,
+                    &owned_playlist_id,
                     None, // fields
-                    Some(SEARCH_LIMIT),
-                    Some(0), // playlist_offset
-                    None,    // market
-                )?;
-                length_tree.insert(playlist_id, &first_page.total.to_be_bytes())?;
-                Ok(PlaylistTracks {
-                    spotify: &self.spotify,
-                    playlist_id: playlist_id.to_string(),
-                    total: first_page.total,
-                    offset: 0,
-                    key,
-                    buffer: first_page.items.into(),
-                    tree: tracks_tree,
-                })
+                    Some(limit),
+                    Some(offset), // playlist_offset
+                    None,         // market
+                )
+            });
+        open_cached_paginated(
+            length_tree,
+            tracks_tree,
+            key_prefix.clone(),
+            key_prefix,
+            force,
+            fetch_page,
+        )
+    }
+
+    /// Iterates the current user's saved tracks, caching pages the same
+    /// way [`CachingSpotify::playlist_tracks`] does, keyed by the
+    /// authenticated user's ID so that switching accounts doesn't serve
+    /// one user's cached saved tracks to another. Pass `force` to discard
+    /// any cached pages and re-fetch from the first page, picking up tracks
+    /// saved or removed since the cache was populated.
+    pub fn saved_tracks(&self, force: bool) -> Result<SavedTracks, Error> {
+        let length_tree = self.db.open_tree("saved_tracks_length")?;
+        let tracks_tree = self.db.open_tree("saved_tracks")?;
+        let key_prefix = user_key_prefix(&self.user_id);
+        if force {
+            clear_cached_pages(&tracks_tree, &length_tree, &key_prefix, &key_prefix)?;
+        }
+        let fetch_page: Box<dyn FnMut(u32, u32) -> Result<Page<SavedTrack>, failure::Error>> =
+            Box::new(move |offset, limit| {
+                self.spotify.current_user_saved_tracks(Some(limit), Some(offset))
+            });
+        open_cached_paginated(
+            length_tree,
+            tracks_tree,
+            key_prefix.clone(),
+            key_prefix,
+            force,
+            fetch_page,
+        )
+    }
+
+    /// Iterates the current user's saved albums, caching pages the same
+    /// way [`CachingSpotify::playlist_tracks`] does, keyed by the
+    /// authenticated user's ID so that switching accounts doesn't serve
+    /// one user's cached saved albums to another. Pass `force` to discard
+    /// any cached pages and re-fetch from the first page, picking up albums
+    /// saved or removed since the cache was populated.
+    pub fn saved_albums(&self, force: bool) -> Result<SavedAlbums, Error> {
+        let length_tree = self.db.open_tree("saved_albums_length")?;
+        let tracks_tree = self.db.open_tree("saved_albums")?;
+        let key_prefix = user_key_prefix(&self.user_id);
+        if force {
+            clear_cached_pages(&tracks_tree, &length_tree, &key_prefix, &key_prefix)?;
+        }
+        let fetch_page: Box<dyn FnMut(u32, u32) -> Result<Page<SavedAlbum>, failure::Error>> =
+            Box::new(move |offset, limit| {
+                self.spotify.current_user_saved_albums(Some(limit), Some(offset))
+            });
+        open_cached_paginated(
+            length_tree,
+            tracks_tree,
+            key_prefix.clone(),
+            key_prefix,
+            force,
+            fetch_page,
+        )
+    }
+
+    /// Iterates the current user's playlists, caching pages the same way
+    /// [`CachingSpotify::playlist_tracks`] does, keyed by the
+    /// authenticated user's ID so that switching accounts doesn't serve
+    /// one user's cached playlists to another. Pass `force` to discard any
+    /// cached pages and re-fetch from the first page, picking up playlists
+    /// followed, created, or removed since the cache was populated.
+    pub fn user_playlists(&self, force: bool) -> Result<UserPlaylists, Error> {
+        let length_tree = self.db.open_tree("user_playlists_length")?;
+        let tracks_tree = self.db.open_tree("user_playlists")?;
+        let key_prefix = user_key_prefix(&self.user_id);
+        if force {
+            clear_cached_pages(&tracks_tree, &length_tree, &key_prefix, &key_prefix)?;
+        }
+        let fetch_page: Box<dyn FnMut(u32, u32) -> Result<Page<SimplifiedPlaylist>, failure::Error>> =
+            Box::new(move |offset, limit| {
+                self.spotify.current_user_playlists(Some(limit), Some(offset))
+            });
+        open_cached_paginated(
+            length_tree,
+            tracks_tree,
+            key_prefix.clone(),
+            key_prefix,
+            force,
+            fetch_page,
+        )
+    }
+
+    /// Computes a set operation (see [`SetOp`]) across two or more
+    /// playlists' tracks, keyed by Spotify track ID. Because
+    /// [`CachingSpotify::playlist_tracks`] already caches each playlist's
+    /// tracks on disk, repeated comparisons across overlapping sets of
+    /// playlists are cheap.
+    pub fn compare_playlists(
+        &self,
+        ids: &[&str],
+        op: SetOp,
+    ) -> Result<Vec<PlaylistTrack>, Error> {
+        let mut sets = Vec::with_capacity(ids.len());
+        for id in ids {
+            let mut tracks = HashMap::new();
+            for track in self.playlist_tracks(id, false)? {
+                let track = track?;
+                tracks.insert(track_key(&track), track);
             }
+            sets.push(tracks);
         }
+
+        let result_keys = apply_set_op(op, sets.iter().map(|tracks| tracks.keys().cloned().collect()));
+
+        let mut combined = HashMap::new();
+        for tracks in &sets {
+            for (key, track) in tracks {
+                combined.entry(key.clone()).or_insert_with(|| track.clone());
+            }
+        }
+        let mut tracks: Vec<PlaylistTrack> = result_keys
+            .into_iter()
+            .filter_map(|key| combined.remove(&key))
+            .collect();
+        tracks.sort_unstable_by(playlist_track_sort_cmp);
+        Ok(tracks)
     }
 }
 
-pub struct PlaylistTracks<'a> {
-    spotify: &'a Spotify,
-    playlist_id: String,
+/// A generic offset-paginated, CBOR-cached iterator over a Spotify "get all
+/// pages" endpoint. `fetch_page(offset, limit)` is called to fetch a page
+/// once the cache is exhausted; each returned item is cached under its own
+/// offset-derived key before being yielded, so repeated iteration (or a
+/// later run against the same cache) re-reads from disk instead of the
+/// network.
+pub struct CachedPaginated<T, F>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(u32, u32) -> Result<Page<T>, failure::Error>,
+{
+    fetch_page: F,
+    limit: u32,
     total: u32,
     offset: u32,
     key: Vec<u8>,
-    buffer: VecDeque<PlaylistTrack>,
+    buffer: VecDeque<T>,
     tree: sled::Tree,
 }
 
-impl PlaylistTracks<'_> {
+impl<T, F> CachedPaginated<T, F>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(u32, u32) -> Result<Page<T>, failure::Error>,
+{
     fn update_key(&mut self, offset: u32) {
         let offset_bytes = u32::to_be_bytes(offset);
         let offset_position = self.key.len() - 4;
@@ -192,8 +517,12 @@ impl PlaylistTracks<'_> {
     }
 }
 
-impl Iterator for PlaylistTracks<'_> {
-    type Item = Result<PlaylistTrack, Error>;
+impl<T, F> Iterator for CachedPaginated<T, F>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnMut(u32, u32) -> Result<Page<T>, failure::Error>,
+{
+    type Item = Result<T, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.offset >= self.total {
@@ -202,34 +531,28 @@ impl Iterator for PlaylistTracks<'_> {
         self.update_key(self.offset);
         match self.tree.get(&self.key) {
             Ok(Some(ivec)) => match serde_cbor::from_reader(ivec.as_ref()) {
-                Ok(track) => {
+                Ok(item) => {
                     self.buffer.pop_front();
                     self.offset += 1;
-                    return Some(Ok(track));
+                    return Some(Ok(item));
                 }
                 Err(e) => error!("Deserialization error reading from cache: {:?}", e),
             },
             Ok(None) => {}
             Err(e) => error!("Database error reading from cache: {:?}", e),
         }
-        if let Some(track) = self.buffer.pop_front() {
+        if let Some(item) = self.buffer.pop_front() {
             self.offset += 1;
-            return Some(Ok(track));
+            return Some(Ok(item));
         }
-        let next_page = match self.spotify.user_playlist_tracks(
-            "", // user id, no longer required
-            &self.playlist_id,
-            None, // fields
-            Some(SEARCH_LIMIT),
-            Some(self.offset), // playlist_offset
-            None,              // market
-        ) {
+        let next_page = match fetch_page_with_retry(&mut self.fetch_page, self.offset, self.limit)
+        {
             Ok(next_page) => next_page,
-            Err(e) => return Some(Err(Error::Failure(e))),
+            Err(e) => return Some(Err(e)),
         };
-        for (i, track) in next_page.items.iter().enumerate() {
+        for (i, item) in next_page.items.iter().enumerate() {
             let mut serialized = Vec::new();
-            if let Err(e) = serde_cbor::to_writer(&mut serialized, track) {
+            if let Err(e) = serde_cbor::to_writer(&mut serialized, item) {
                 return Some(Err(Error::Cbor(e)));
             }
             self.update_key(self.offset + i as u32);
@@ -238,16 +561,129 @@ impl Iterator for PlaylistTracks<'_> {
             }
         }
         self.buffer = next_page.items.into();
-        let maybe_track = self.buffer.pop_front();
+        let maybe_item = self.buffer.pop_front();
         self.offset += 1;
-        maybe_track.map(Result::Ok)
+        maybe_item.map(Result::Ok)
     }
 }
 
+pub type PlaylistTracks<'a> = CachedPaginated<
+    PlaylistTrack,
+    Box<dyn FnMut(u32, u32) -> Result<Page<PlaylistTrack>, failure::Error> + 'a>,
+>;
+
+pub type SavedTracks<'a> = CachedPaginated<
+    SavedTrack,
+    Box<dyn FnMut(u32, u32) -> Result<Page<SavedTrack>, failure::Error> + 'a>,
+>;
+
+pub type SavedAlbums<'a> = CachedPaginated<
+    SavedAlbum,
+    Box<dyn FnMut(u32, u32) -> Result<Page<SavedAlbum>, failure::Error> + 'a>,
+>;
+
+pub type UserPlaylists<'a> = CachedPaginated<
+    SimplifiedPlaylist,
+    Box<dyn FnMut(u32, u32) -> Result<Page<SimplifiedPlaylist>, failure::Error> + 'a>,
+>;
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn retry_delay_secs_uses_retry_after_when_present() {
+        let error: failure::Error = ApiError::RateLimited(Some(30)).into();
+        assert_eq!(retry_delay_secs(1, &error), 30);
+    }
+
+    #[test]
+    fn retry_delay_secs_falls_back_to_default_when_retry_after_absent() {
+        let error: failure::Error = ApiError::RateLimited(None).into();
+        assert_eq!(retry_delay_secs(1, &error), DEFAULT_RATE_LIMIT_RETRY_SECS);
+    }
+
+    #[test]
+    fn retry_delay_secs_backs_off_exponentially_for_other_errors() {
+        let error = failure::err_msg("transient failure");
+        assert_eq!(retry_delay_secs(1, &error), DEFAULT_RATE_LIMIT_RETRY_SECS);
+        assert_eq!(retry_delay_secs(2, &error), DEFAULT_RATE_LIMIT_RETRY_SECS * 2);
+        assert_eq!(retry_delay_secs(3, &error), DEFAULT_RATE_LIMIT_RETRY_SECS * 4);
+    }
+
+    #[test]
+    fn retry_with_backoff_returns_ok_without_retrying() {
+        let mut calls = 0;
+        let result: Result<i32, Error> = retry_with_backoff(|| {
+            calls += 1;
+            Ok(42)
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_with_backoff_gives_up_after_max_retries() {
+        let mut calls = 0;
+        // Retry-After of 0 keeps this test fast while still exercising the
+        // MAX_RETRIES cutoff.
+        let result: Result<(), Error> = retry_with_backoff(|| {
+            calls += 1;
+            Err(ApiError::RateLimited(Some(0)).into())
+        });
+        assert!(matches!(result, Err(Error::Failure(_))));
+        assert_eq!(calls, MAX_RETRIES + 1);
+    }
+
+    #[test]
+    fn local_track_key_differs_for_distinct_local_tracks() {
+        let a = local_track_key("Track A", &["Artist"], "Album", 1000);
+        let b = local_track_key("Track B", &["Artist"], "Album", 1000);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn local_track_key_is_stable_for_identical_input() {
+        let a = local_track_key("Track", &["Artist One", "Artist Two"], "Album", 1000);
+        let b = local_track_key("Track", &["Artist One", "Artist Two"], "Album", 1000);
+        assert_eq!(a, b);
+    }
+
+    fn set(keys: &[&str]) -> HashSet<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn apply_set_op_empty_input_yields_empty_set() {
+        assert_eq!(apply_set_op(SetOp::Union, std::iter::empty()), HashSet::new());
+    }
+
+    #[test]
+    fn apply_set_op_intersection_keeps_only_shared_keys() {
+        let sets = vec![set(&["a", "b", "c"]), set(&["b", "c", "d"]), set(&["b", "e"])];
+        assert_eq!(
+            apply_set_op(SetOp::Intersection, sets.into_iter()),
+            set(&["b"])
+        );
+    }
+
+    #[test]
+    fn apply_set_op_difference_removes_keys_present_in_any_other_set() {
+        let sets = vec![set(&["a", "b", "c"]), set(&["b"]), set(&["c"])];
+        assert_eq!(
+            apply_set_op(SetOp::Difference, sets.into_iter()),
+            set(&["a"])
+        );
+    }
+
+    #[test]
+    fn apply_set_op_union_keeps_every_key() {
+        let sets = vec![set(&["a", "b"]), set(&["b", "c"])];
+        assert_eq!(apply_set_op(SetOp::Union, sets.into_iter()), set(&["a", "b", "c"]));
+    }
 }